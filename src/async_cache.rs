@@ -0,0 +1,303 @@
+//! A cache that can be used from async code.
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::path;
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use tokio::io::AsyncWriteExt;
+
+use crate::db;
+use crate::error::Error;
+use crate::read_only::Offline;
+use crate::reqwest_mock::{AsyncClient, AsyncHttpResponse, HttpResponse};
+use crate::{fetch_url, Cache, RequestExt};
+
+/// A local cache of HTTP resources for use from async code.
+///
+/// This behaves like [`Cache`],
+/// except that it sends requests with an [`AsyncClient`]
+/// (such as `reqwest::Client`)
+/// and its [`get`](Self::get()) method is `async`.
+///
+/// The cache metadata and content are still read and written
+/// with blocking calls, but the network is never waited on.
+///
+/// This is only available with the `async` feature.
+#[derive(Debug)]
+pub struct AsyncCache<C: AsyncClient> {
+    inner: Cache<Offline>,
+    client: C,
+}
+
+/// A response whose body has been downloaded to a temporary file,
+/// so it can be stored like a blocking response.
+#[derive(Debug)]
+struct Downloaded {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: fs::File,
+    url: reqwest::Url,
+}
+
+impl HttpResponse for Downloaded {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+    fn error_for_status(self) -> Result<Self, Error> {
+        Ok(self)
+    }
+    fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+}
+
+impl Read for Downloaded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// Download the body of `response` to a temporary file in `root`.
+async fn download<R: AsyncHttpResponse>(
+    mut response: R,
+    root: &path::Path,
+) -> Result<Downloaded, Error> {
+    let mut body = tokio::fs::File::from_std(tempfile::tempfile_in(root)?);
+    while let Some(chunk) = response.chunk().await? {
+        body.write_all(&chunk).await?;
+    }
+    body.flush().await?;
+
+    let mut body = body.into_std().await;
+    body.seek(io::SeekFrom::Start(0))?;
+
+    Ok(Downloaded {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body,
+        url: response.url().clone(),
+    })
+}
+
+impl<C: AsyncClient> AsyncCache<C> {
+    /// Returns an AsyncCache that wraps `client` and caches data in `root`.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::new`] might.
+    pub fn new(root: path::PathBuf, client: C) -> Result<AsyncCache<C>, Error> {
+        Ok(AsyncCache {
+            inner: Cache::new(root, Offline)?,
+            client,
+        })
+    }
+
+    /// Retrieve the content of the given URL.
+    ///
+    /// See [`Cache::get`] for details.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::get`] might.
+    pub async fn get(
+        &mut self,
+        mut url: reqwest::Url,
+    ) -> Result<tokio::fs::File, Error> {
+        url.set_fragment(None);
+        self.inner.check_host(&url)?;
+
+        let record = self.inner.usable_record(&url, &[]);
+        if let Some(record) = &record {
+            if self.inner.is_fresh(record) {
+                debug!("Cached copy of {} is still fresh", url);
+                return self.open(&url, record);
+            }
+        }
+
+        let mut request = reqwest::Request::new(
+            reqwest::Method::GET,
+            fetch_url(
+                self.inner.trailing_slash_policy,
+                self.inner.url_rewriter.as_ref(),
+                &url,
+            ),
+        );
+        request.set_user_agent(self.inner.user_agent.as_ref());
+        request.apply_host_config(&self.inner.host_configs);
+        if let Some(record) = &record {
+            request
+                .headers_mut()
+                .extend(self.inner.conditional_headers(record)?);
+        }
+
+        info!("Sending HTTP request: {:?}", request);
+        let response = self
+            .client
+            .execute(request)
+            .await
+            .and_then(|response| response.error_for_status());
+
+        let response = match (response, record) {
+            (Ok(response), Some(record))
+                if response.status() == StatusCode::NOT_MODIFIED =>
+            {
+                self.inner.record_not_modified(
+                    &url,
+                    &[],
+                    response.headers(),
+                )?;
+                self.inner.db.set_dirty(&record.path, false)?;
+
+                return self.open(&url, &record);
+            }
+            (Ok(response), _) => response,
+            (Err(e), Some(record)) => {
+                if let Some(decide) = &self.inner.fallback_decider {
+                    if !decide(&e) {
+                        return Err(e);
+                    }
+                }
+
+                warn!("Could not validate cached response: {}", e);
+
+                if let Some(observer) = &self.inner.fallback_observer {
+                    observer(&url, &e);
+                }
+
+                return self.open(&url, &record);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        info!("Got HTTP response: {:?}", response);
+
+        let mut response = download(response, &self.inner.root).await?;
+        let (_, record) = self.inner.store_and_record(
+            url.clone(),
+            &[],
+            &mut response,
+            &mut io::sink(),
+        )?;
+
+        self.open(&url, &record)
+    }
+
+    /// Open the content of `url` described by `record`.
+    fn open(
+        &self,
+        url: &reqwest::Url,
+        record: &db::CacheRecord,
+    ) -> Result<tokio::fs::File, Error> {
+        Ok(tokio::fs::File::from_std(
+            self.inner.open_content(url, record)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use reqwest::header as rh;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use tokio::io::AsyncReadExt;
+
+    use super::AsyncCache;
+    use crate::reqwest_mock::tests as rmt;
+
+    const DATE_ZERO: &str = "Thu, 01 Jan 1970 00:00:00 GMT";
+
+    fn make_test_cache(client: rmt::FakeClient) -> AsyncCache<rmt::FakeClient> {
+        AsyncCache::new(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn initial_request_success() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let body = b"hello world";
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(body.as_ref().into()),
+                ..Default::default()
+            },
+        ));
+
+        // We should get a file-handle containing the body bytes.
+        let mut res = c.get(url).await.unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(&buf, body);
+        c.client.assert_called();
+    }
+
+    #[tokio::test]
+    async fn use_cache_data_if_not_modified_since() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let body = b"hello world";
+
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(rh::LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(body.as_ref().into()),
+                ..Default::default()
+            },
+        ));
+
+        c.get(url.clone()).await.unwrap();
+        c.client.assert_called();
+
+        // The next request should ask whether the content has changed.
+        let mut second_request = HeaderMap::new();
+        second_request
+            .append(rh::IF_MODIFIED_SINCE, HeaderValue::from_static(DATE_ZERO));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(b""[..].into()),
+                ..Default::default()
+            },
+        );
+
+        // The cached body is used, though the response had none.
+        let mut res = c.get(url).await.unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(&buf, body);
+        c.client.assert_called();
+    }
+}
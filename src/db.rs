@@ -1,24 +1,184 @@
 use std::cmp;
+use std::collections::HashMap;
 use std::ffi;
 use std::fmt;
+use std::io;
 use std::iter;
 use std::path;
+use std::thread;
+use std::time;
 
 use sqlite::Value;
 
 use crate::error::Error;
+use crate::{Order, SortKey};
+
+/// An expression that sorts `Last-Modified` dates in date order.
+///
+/// Dates are stored as HTTP dates, like `Thu, 01 Jan 1970 00:00:00 GMT`,
+/// which sort by weekday if compared as text,
+/// so they are rearranged as `19700101 00:00:00`.
+const LAST_MODIFIED_ORDER: &str = "
+    substr(last_modified, 13, 4)
+    || printf('%02d', (instr('JanFebMarAprMayJunJulAugSepOctNovDec',
+        substr(last_modified, 9, 3)) + 2) / 3)
+    || substr(last_modified, 6, 2)
+    || substr(last_modified, 17, 9)
+";
+
+/// Older versions of cached content, for caches that keep history.
+///
+/// Like `urls`, this table gets the columns in [`ADDED_COLUMNS`]
+/// when the schema is upgraded.
+const VERSIONS_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS versions (
+    	url TEXT NOT NULL,
+    	path TEXT NOT NULL,
+    	last_modified TEXT,
+    	etag TEXT
+    );
+";
+
+/// Downloads that were interrupted, and can be resumed.
+///
+/// What was downloaded is kept in a file named after the URL,
+/// so only its size and the validator it must match are recorded.
+const PARTIALS_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS partials (
+    	url TEXT NOT NULL UNIQUE,
+    	size INTEGER NOT NULL,
+    	validator TEXT NOT NULL
+    );
+";
+
+/// Notes the application has attached to cached URLs.
+///
+/// Each is keyed by the URL's cache key,
+/// and is forgotten along with the URL.
+const ANNOTATIONS_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS annotations (
+    	url TEXT NOT NULL,
+    	key TEXT NOT NULL,
+    	value TEXT NOT NULL,
+    	UNIQUE (url, key)
+    );
+";
+
+/// Removes annotations for keys that are no longer cached,
+/// under their own key or as a variant.
+const ORPHANED_ANNOTATIONS_SQL: &str = "
+    DELETE FROM annotations
+    WHERE NOT EXISTS (
+        SELECT 1 FROM urls
+        WHERE urls.url = annotations.url
+        OR substr(urls.url, 1, length(annotations.url) + 1)
+            = annotations.url || '#'
+    );
+";
+
+/// How long to wait for another connection to finish writing, in milliseconds.
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+/// How many times to retry a commit that failed
+/// because the database was busy, unless configured otherwise.
+const DEFAULT_COMMIT_RETRIES: usize = 3;
+
+/// How long to wait before the first retry of a busy commit.
+///
+/// Each later retry waits twice as long as the one before.
+const COMMIT_BACKOFF: time::Duration = time::Duration::from_millis(10);
+
+/// The most parameters SQLite accepts in one statement,
+/// in the oldest versions we might be linked against.
+const MAX_QUERY_PARAMS: usize = 999;
+
+/// The SQLite result codes for a database that is busy or locked.
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
 
 const SCHEMA_SQL: &str = "
     CREATE TABLE urls (
     	url TEXT NOT NULL UNIQUE,
     	path TEXT NOT NULL,
     	last_modified TEXT,
-    	etag TEXT
+    	etag TEXT,
+    	size INTEGER,
+    	expires INTEGER,
+    	downloaded_at INTEGER,
+    	sha256 TEXT,
+    	content_type TEXT,
+    	compressed INTEGER,
+    	last_accessed INTEGER,
+    	custom_validator TEXT,
+    	dirty INTEGER,
+    	pinned INTEGER,
+    	source TEXT,
+    	body BLOB,
+    	headers TEXT,
+    	status INTEGER,
+    	request_headers TEXT,
+    	content_length INTEGER,
+    	final_url TEXT
     );
 ";
 
+/// Columns added to the `urls` table since the first release,
+/// and their types.
+///
+/// Databases created by older versions are upgraded
+/// by adding whichever of these columns they lack.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("size", "INTEGER"),
+    ("expires", "INTEGER"),
+    ("downloaded_at", "INTEGER"),
+    ("sha256", "TEXT"),
+    ("content_type", "TEXT"),
+    ("compressed", "INTEGER"),
+    ("last_accessed", "INTEGER"),
+    ("custom_validator", "TEXT"),
+    ("dirty", "INTEGER"),
+    ("pinned", "INTEGER"),
+    ("source", "TEXT"),
+    ("body", "BLOB"),
+    ("headers", "TEXT"),
+    ("status", "INTEGER"),
+    ("request_headers", "TEXT"),
+    ("content_length", "INTEGER"),
+    ("final_url", "TEXT"),
+];
+
+/// The columns of the `urls` table that make up a [`CacheRecord`],
+/// in the order [`CacheRecord::from_row`] expects them.
+///
+/// The `pinned` and `source` columns are not part of a record,
+/// since they belong to the URL rather than to any one response.
+const RECORD_COLUMNS: &str = "
+    path,
+    last_modified,
+    etag,
+    size,
+    expires,
+    downloaded_at,
+    sha256,
+    content_type,
+    compressed,
+    last_accessed,
+    custom_validator,
+    dirty,
+    body,
+    headers,
+    status,
+    request_headers,
+    content_length,
+    final_url
+";
+
+/// The key, content path and recorded body size of a cached URL,
+/// and whether it is pinned, from [`CacheDB::sizes`].
+pub type EntrySize = (String, String, Option<u64>, bool);
+
 /// All the information we have about a given URL.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CacheRecord {
     /// The path to the cached response body on disk.
     pub path: String,
@@ -26,6 +186,111 @@ pub struct CacheRecord {
     pub last_modified: Option<String>,
     /// The value of the Etag header in the original response.
     pub etag: Option<String>,
+    /// The size of the cached response body, in bytes.
+    pub size: Option<u64>,
+    /// The time after which the cached response is stale.
+    pub expires: Option<time::SystemTime>,
+    /// The time the cached response was downloaded.
+    pub downloaded_at: Option<time::SystemTime>,
+    /// The hex-encoded SHA-256 digest of the cached response body.
+    pub sha256: Option<String>,
+    /// The value of the Content-Type header in the original response.
+    pub content_type: Option<String>,
+    /// Whether the cached response body is stored gzip-compressed.
+    pub compressed: bool,
+    /// The last time the cached response body was used.
+    pub last_accessed: Option<time::SystemTime>,
+    /// The value of the custom validator header in the original response,
+    /// if one is configured.
+    pub custom_validator: Option<String>,
+    /// Whether the cached response was used without being revalidated,
+    /// and should be revalidated before it's used again.
+    pub dirty: bool,
+    /// The response body itself,
+    /// if it is small enough to be stored in the database
+    /// instead of in the file at `path`.
+    pub body: Option<Vec<u8>>,
+    /// The headers of the original response that were kept,
+    /// one `name: value` pair per line.
+    pub headers: Option<String>,
+    /// The status code of the original response.
+    pub status: Option<u16>,
+    /// The additional headers the original request was sent with,
+    /// except those that may contain credentials,
+    /// one `name: value` pair per line.
+    pub request_headers: Option<String>,
+    /// The value of the Content-Length header in the original response,
+    /// which the cached body was checked against.
+    pub content_length: Option<u64>,
+    /// The URL the original response came from,
+    /// if the request was redirected there.
+    pub final_url: Option<String>,
+}
+
+impl CacheRecord {
+    /// Build a record from the values of [`RECORD_COLUMNS`].
+    fn from_row(row: Vec<Value>) -> Result<CacheRecord, Error> {
+        let mut cols = row.into_iter();
+
+        let path = match cols.next().unwrap() {
+            Value::String(s) => s,
+            other => return Err(Error::WrongPathType(format!("{:?}", other))),
+        };
+
+        Ok(CacheRecord {
+            path,
+            last_modified: optional_text(cols.next().unwrap(), "last_modified"),
+            etag: optional_text(cols.next().unwrap(), "etag"),
+            size: optional_size(cols.next().unwrap()),
+            expires: optional_time(cols.next().unwrap(), "expires"),
+            downloaded_at: optional_time(cols.next().unwrap(), "downloaded_at"),
+            sha256: optional_text(cols.next().unwrap(), "sha256"),
+            content_type: optional_text(cols.next().unwrap(), "content_type"),
+            compressed: optional_integer(cols.next().unwrap(), "compressed")
+                .is_some_and(|i| i != 0),
+            last_accessed: optional_time(cols.next().unwrap(), "last_accessed"),
+            custom_validator: optional_text(
+                cols.next().unwrap(),
+                "custom_validator",
+            ),
+            dirty: optional_integer(cols.next().unwrap(), "dirty")
+                .is_some_and(|i| i != 0),
+            body: optional_blob(cols.next().unwrap(), "body"),
+            headers: optional_text(cols.next().unwrap(), "headers"),
+            status: optional_integer(cols.next().unwrap(), "status")
+                .map(|i| i as u16),
+            request_headers: optional_text(
+                cols.next().unwrap(),
+                "request_headers",
+            ),
+            content_length: optional_size(cols.next().unwrap()),
+            final_url: optional_text(cols.next().unwrap(), "final_url"),
+        })
+    }
+
+    /// Return the values of [`RECORD_COLUMNS`] for this record.
+    fn into_values(self) -> Vec<Value> {
+        vec![
+            Value::String(self.path),
+            text_value(self.last_modified),
+            text_value(self.etag),
+            integer_value(self.size.map(|size| size as i64)),
+            time_value(self.expires),
+            time_value(self.downloaded_at),
+            text_value(self.sha256),
+            text_value(self.content_type),
+            Value::Integer(self.compressed as i64),
+            time_value(self.last_accessed),
+            text_value(self.custom_validator),
+            Value::Integer(self.dirty as i64),
+            self.body.map(Value::Binary).unwrap_or(Value::Null),
+            text_value(self.headers),
+            integer_value(self.status.map(i64::from)),
+            text_value(self.request_headers),
+            integer_value(self.content_length.map(|len| len as i64)),
+            text_value(self.final_url),
+        ]
+    }
 }
 
 /// Represents the rows returned by a query.
@@ -45,17 +310,66 @@ impl<'a> iter::Iterator for Rows<'a> {
     }
 }
 
+/// Every cached URL, and what the DB knows about it,
+/// read from the DB one at a time.
+pub struct Records<'a>(Rows<'a>);
+
+impl<'a> iter::Iterator for Records<'a> {
+    type Item = Result<(reqwest::Url, CacheRecord), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for row in &mut self.0 {
+            let mut cols = row.into_iter();
+
+            let url = match cols.next().unwrap() {
+                Value::String(s) => s.parse::<reqwest::Url>(),
+                other => {
+                    warn!("url contained weird type: {:?}", other);
+                    continue;
+                }
+            };
+
+            match url {
+                // Variants are stored under keys that look like URLs
+                // with fragments, but they aren't URLs in their own right.
+                Ok(url) if url.fragment().is_some() => continue,
+                Ok(url) => {
+                    return Some(
+                        CacheRecord::from_row(cols.collect())
+                            .map(|record| (url, record)),
+                    )
+                }
+                Err(err) => warn!("Cache contains invalid URL: {}", err),
+            }
+        }
+
+        None
+    }
+}
+
+/// An interrupted download.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Partial {
+    /// How many bytes of the body were downloaded.
+    pub size: u64,
+    /// The `ETag` or `Last-Modified` header the rest of the body
+    /// must be downloaded with.
+    pub validator: String,
+}
+
 /// Represents an attempt to record information in the database.
 #[must_use]
 pub struct Transaction<'a> {
     conn: &'a sqlite::Connection,
+    retries: usize,
     committed: bool,
 }
 
 impl<'a> Transaction<'a> {
-    fn new(conn: &'a sqlite::Connection) -> Transaction<'a> {
+    fn new(conn: &'a sqlite::Connection, retries: usize) -> Transaction<'a> {
         Transaction {
             conn,
+            retries,
             committed: false,
         }
     }
@@ -64,7 +378,8 @@ impl<'a> Transaction<'a> {
         debug!("Attempting to commit changes...");
         self.committed = true;
 
-        self.conn.execute("COMMIT;").map_err(|err| {
+        let res = retry_if_busy(self.retries, || self.conn.execute("COMMIT;"));
+        res.map_err(|err| {
             debug!("Failed to commit changes: {}", err);
             match self.conn.execute("ROLLBACK;") {
                 // Rollback worked, return the original error
@@ -95,6 +410,52 @@ impl<'a> Drop for Transaction<'a> {
     }
 }
 
+/// Return true if `err` means another connection
+/// is using the database.
+fn is_busy(err: &sqlite::Error) -> bool {
+    // Extended result codes keep the primary code in the low byte.
+    matches!(
+        err.code.map(|code| code & 0xff),
+        Some(SQLITE_BUSY) | Some(SQLITE_LOCKED)
+    )
+}
+
+/// Call `f` until it succeeds, fails for a reason other than
+/// the database being busy, or has been retried `retries` times,
+/// and return its last result.
+///
+/// This is only safe if calling `f` again after a busy error
+/// has the same effect as calling it once,
+/// as is the case for `COMMIT`.
+fn retry_if_busy<F>(retries: usize, mut f: F) -> Result<(), sqlite::Error>
+where
+    F: FnMut() -> Result<(), sqlite::Error>,
+{
+    let mut backoff = COMMIT_BACKOFF;
+
+    for _ in 0..retries {
+        match f() {
+            Err(err) if is_busy(&err) => {
+                debug!("Database busy ({}), retrying in {:?}", err, backoff);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+
+    f()
+}
+
+/// Return the key under which a variant of the content at `key` is stored,
+/// when the server says the response varies with the Accept header.
+///
+/// The key looks like `key` with a fragment,
+/// so it can't be mistaken for the key of an ordinary URL.
+pub fn variant_key(key: &str, accept: &str) -> String {
+    format!("{}#accept={}", key, accept)
+}
+
 fn canonicalize_db_path(path: path::PathBuf) -> Result<path::PathBuf, Error> {
     let mem_path: ffi::OsString = ":memory:".into();
 
@@ -117,6 +478,7 @@ fn canonicalize_db_path(path: path::PathBuf) -> Result<path::PathBuf, Error> {
 pub struct CacheDB {
     path: path::PathBuf,
     conn: sqlite::Connection,
+    commit_retries: usize,
 }
 
 impl CacheDB {
@@ -124,11 +486,19 @@ impl CacheDB {
     pub fn new(path: path::PathBuf) -> Result<CacheDB, Error> {
         let path = canonicalize_db_path(path)?;
         debug!("Creating cache metadata in {:?}", path);
-        let conn = sqlite::Connection::open(&path)?;
+        let mut conn = sqlite::Connection::open(&path)?;
+
+        // Other connections may be writing to the same database,
+        // so wait a while for them to finish rather than failing.
+        conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
 
         // Package up the return value first, so we can use .query()
         // instead of wrangling sqlite directly.
-        let res = CacheDB { path, conn };
+        let res = CacheDB {
+            path,
+            conn,
+            commit_retries: DEFAULT_COMMIT_RETRIES,
+        };
 
         let rows: Vec<_> = res
             .query("SELECT COUNT(*) FROM sqlite_master;", &[])?
@@ -138,9 +508,58 @@ impl CacheDB {
             res.conn.execute(SCHEMA_SQL)?
         }
 
+        res.conn.execute(VERSIONS_SQL)?;
+        res.conn.execute(PARTIALS_SQL)?;
+        res.conn.execute(ANNOTATIONS_SQL)?;
+        res.upgrade_schema()?;
+
         Ok(res)
     }
 
+    /// Add any columns the `urls` and `versions` tables are missing.
+    fn upgrade_schema(&self) -> Result<(), Error> {
+        for table in &["urls", "versions"] {
+            let existing: Vec<String> = self
+                .query(format!("PRAGMA table_info({});", table), &[])?
+                .filter_map(|row| match row.into_iter().nth(1) {
+                    Some(Value::String(name)) => Some(name),
+                    _ => None,
+                })
+                .collect();
+
+            for (name, kind) in ADDED_COLUMNS {
+                if !existing.iter().any(|each| each == name) {
+                    debug!(
+                        "Adding column {:?} to {} in the cache DB.",
+                        name, table
+                    );
+                    self.conn.execute(format!(
+                        "ALTER TABLE {} ADD COLUMN {} {};",
+                        table, name, kind,
+                    ))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open an existing cache database without modifying it.
+    pub fn open_read_only(path: path::PathBuf) -> Result<CacheDB, Error> {
+        let path = canonicalize_db_path(path)?;
+        debug!("Opening read-only cache metadata in {:?}", path);
+        let conn = sqlite::Connection::open_with_flags(
+            &path,
+            sqlite::OpenFlags::new().set_read_only(),
+        )?;
+
+        Ok(CacheDB {
+            path,
+            conn,
+            commit_retries: DEFAULT_COMMIT_RETRIES,
+        })
+    }
+
     fn query<'a, T: AsRef<str>>(
         &'a self,
         query: T,
@@ -157,57 +576,138 @@ impl CacheDB {
         Ok(Rows(cur))
     }
 
+    /// Call `f` with the underlying SQLite connection.
+    pub fn with_connection<R>(
+        &self,
+        f: impl FnOnce(&sqlite::Connection) -> R,
+    ) -> R {
+        f(&self.conn)
+    }
+
+    /// Write a consistent snapshot of the database to a new file at `dest`.
+    ///
+    /// Other connections can keep using the database meanwhile.
+    pub fn backup(&self, dest: &path::Path) -> Result<(), Error> {
+        let dest = dest.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("backup path is not valid UTF-8: {:?}", dest),
+            )
+        })?;
+
+        let rows =
+            self.query("VACUUM INTO ?1;", &[Value::String(dest.into())])?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
     /// Return what the DB knows about a URL, if anything.
+    ///
+    /// The cache looks records up by key, with [`get_key`](Self::get_key());
+    /// this is only for tests.
+    #[cfg(test)]
     pub fn get(&self, mut url: reqwest::Url) -> Result<CacheRecord, Error> {
         url.set_fragment(None);
 
+        match self.get_key(url.as_str())? {
+            Some(record) => Ok(record),
+            None => Err(Error::URLNotFound(url)),
+        }
+    }
+
+    /// Return what the DB knows about a key, if anything.
+    ///
+    /// Keys are usually URLs, but see [`variant_key`].
+    pub fn get_key(&self, key: &str) -> Result<Option<CacheRecord>, Error> {
         let mut rows = self.query(
-            "
-            SELECT path, last_modified, etag
-            FROM urls
-            WHERE url = ?1
-            ",
-            &[Value::String(url.as_str().into())],
+            format!(
+                "
+                SELECT {}
+                FROM urls
+                WHERE url = ?1
+                ",
+                RECORD_COLUMNS,
+            ),
+            &[Value::String(key.into())],
         )?;
 
-        rows.next()
-            .map_or_else(
-                || Err(Error::URLNotFound(url.clone())),
-                Ok,
-            )
-            .map(|row: Vec<Value>| -> Result<CacheRecord, Error> {
-                let mut cols = row.into_iter();
+        let record = match rows.next() {
+            Some(row) => CacheRecord::from_row(row)?,
+            None => return Ok(None),
+        };
+
+        debug!(
+            "Cache says key {:?} content is at {:?}, etag {:?}, last modified at {:?}",
+            key, record.path, record.etag, record.last_modified,
+        );
 
-                let path = match cols.next().unwrap() {
-                    Value::String(s) => Ok(s),
-                    other => Err(Error::WrongPathType(format!("{:?}", other))),
-                }?;
+        Ok(Some(record))
+    }
 
-                let last_modified = match cols.next().unwrap() {
-                    Value::String(s) => Some(s),
-                    Value::Null => None,
-                    other => {
-                        warn!(
-                            "last_modified contained weird type: {:?}",
-                            other,
-                        );
-                        None
-                    },
-                };
+    /// Return what the DB knows about each of `keys` that is cached,
+    /// keyed by the cache key.
+    ///
+    /// Keys are looked up a chunk at a time,
+    /// rather than with one query each.
+    pub fn get_keys(
+        &self,
+        keys: &[String],
+    ) -> Result<HashMap<String, CacheRecord>, Error> {
+        let mut records = HashMap::new();
+
+        for chunk in keys.chunks(MAX_QUERY_PARAMS) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let params: Vec<Value> =
+                chunk.iter().map(|key| Value::String(key.clone())).collect();
+
+            let rows = self.query(
+                format!(
+                    "SELECT url, {} FROM urls WHERE url IN ({});",
+                    RECORD_COLUMNS, placeholders,
+                ),
+                &params,
+            )?;
+
+            for row in rows {
+                let mut cols = row.into_iter();
 
-                let etag = match cols.next().unwrap() {
-                    Value::String(s) => Some(s),
-                    Value::Null => None,
+                let key = match cols.next().unwrap() {
+                    Value::String(key) => key,
                     other => {
-                        warn!("etag contained weird type: {:?}", other);
-                        None
-                    },
+                        warn!("url contained weird type: {:?}", other);
+                        continue;
+                    }
                 };
 
-                debug!("Cache says URL {:?} content is at {:?}, etag {:?}, last modified at {:?}", url, path, etag, last_modified);
+                records.insert(key, CacheRecord::from_row(cols.collect())?);
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Return what the DB knows about a URL
+    /// whose content came from `final_url`,
+    /// whether it was requested directly or redirected there.
+    pub fn get_by_final_url(
+        &self,
+        final_url: &str,
+    ) -> Result<Option<CacheRecord>, Error> {
+        let mut rows = self.query(
+            format!(
+                "SELECT {} FROM urls WHERE final_url = ?1 OR url = ?1 LIMIT 1;",
+                RECORD_COLUMNS,
+            ),
+            &[Value::String(final_url.into())],
+        )?;
 
-                Ok(CacheRecord{path, last_modified, etag})
-            })?
+        match rows.next() {
+            Some(row) => Ok(Some(CacheRecord::from_row(row)?)),
+            None => Ok(None),
+        }
     }
 
     /// Record information about this information in the database.
@@ -218,6 +718,34 @@ impl CacheDB {
     ) -> Result<Transaction, Error> {
         url.set_fragment(None);
 
+        self.set_key(url.as_str(), record)
+    }
+
+    /// Set how many times a commit is retried
+    /// if another connection is using the database.
+    pub fn set_commit_retries(&mut self, retries: usize) {
+        self.commit_retries = retries;
+    }
+
+    /// Return how many times a busy commit is retried.
+    pub fn commit_retries(&self) -> usize {
+        self.commit_retries
+    }
+
+    /// Return whether this database is held in memory,
+    /// rather than in a file.
+    pub fn is_in_memory(&self) -> bool {
+        self.path == path::Path::new(":memory:")
+    }
+
+    /// Record information about a key in the database.
+    ///
+    /// Keys are usually URLs, but see [`variant_key`].
+    pub fn set_key(
+        &mut self,
+        key: &str,
+        record: CacheRecord,
+    ) -> Result<Transaction<'_>, Error> {
         // TODO: Consider using the "pre-poop-your-pants" pattern to
         // ensure the transaction gets cleaned up even if somebody calls
         // mem::forget() on the Transaction object.
@@ -227,33 +755,690 @@ impl CacheDB {
 
         // ...and immediately construct the value that will clean up
         // the transaction when necessary.
-        let res = Transaction::new(&self.conn);
+        let res = Transaction::new(&self.conn, self.commit_retries);
+
+        let mut values = vec![Value::String(key.into())];
+        values.extend(record.into_values());
+
+        let placeholders: Vec<String> =
+            (1..=values.len()).map(|i| format!("?{}", i)).collect();
+
+        let rows = self.query(
+            format!(
+                "
+                INSERT OR REPLACE INTO urls
+                    (url, {}, pinned, source)
+                VALUES (
+                    {},
+                    (SELECT pinned FROM urls WHERE url = ?1),
+                    (SELECT source FROM urls WHERE url = ?1)
+                );
+                ",
+                RECORD_COLUMNS,
+                placeholders.join(", "),
+            ),
+            &values,
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(res)
+    }
+
+    /// Return every cached URL, and what the DB knows about it.
+    pub fn records(&self) -> Result<Vec<(reqwest::Url, CacheRecord)>, Error> {
+        self.iter_records()?.collect()
+    }
+
+    /// Like [`records`](Self::records()),
+    /// but reads each record as it is needed.
+    pub fn iter_records(&self) -> Result<Records<'_>, Error> {
+        let rows = self
+            .query(format!("SELECT url, {} FROM urls;", RECORD_COLUMNS), &[])?;
+
+        Ok(Records(rows))
+    }
+
+    /// Return at most `limit` cached URLs, and what the DB knows about them,
+    /// sorted by `key` in `order`,
+    /// skipping the first `offset`.
+    ///
+    /// URLs that sort the same are sorted by URL,
+    /// so that pages don't overlap.
+    pub fn sorted_records(
+        &self,
+        key: SortKey,
+        order: Order,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(reqwest::Url, CacheRecord)>, Error> {
+        let column = match key {
+            SortKey::Url => "url",
+            SortKey::Size => "size",
+            SortKey::LastAccessed => "last_accessed",
+            SortKey::LastModified => LAST_MODIFIED_ORDER,
+        };
+        let direction = match order {
+            Order::Ascending => "ASC",
+            Order::Descending => "DESC",
+        };
+
+        // Variants aren't listed, so they mustn't count towards the limit.
+        let rows = self.query(
+            format!(
+                "
+                SELECT url, {} FROM urls
+                WHERE instr(url, '#') = 0
+                ORDER BY {} {}, url {}
+                LIMIT ?1 OFFSET ?2;
+                ",
+                RECORD_COLUMNS, column, direction, direction,
+            ),
+            &[
+                Value::Integer(limit.min(i64::MAX as usize) as i64),
+                Value::Integer(offset.min(i64::MAX as usize) as i64),
+            ],
+        )?;
+
+        Records(rows).collect()
+    }
+
+    /// Record a new expiry time for a key that is already cached.
+    pub fn set_expires(
+        &mut self,
+        key: &str,
+        expires: Option<time::SystemTime>,
+    ) -> Result<(), Error> {
+        let rows = self.query(
+            "UPDATE urls SET expires = ?2 WHERE url = ?1;",
+            &[Value::String(key.into()), time_value(expires)],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Record whether `key`, and every variant of it, is pinned.
+    ///
+    /// Returns false if `key` is not cached.
+    pub fn set_pinned(
+        &mut self,
+        key: &str,
+        pinned: bool,
+    ) -> Result<bool, Error> {
+        // Variant keys are the URL followed by a fragment.
+        let matching = "
+            WHERE url = ?1
+            OR substr(url, 1, length(?1) + 1) = ?1 || '#'
+        ";
+
+        let rows: Vec<_> = self
+            .query(
+                format!("SELECT COUNT(*) FROM urls {};", matching),
+                &[Value::String(key.into())],
+            )?
+            .collect();
+        if let Value::Integer(0) = rows[0][0] {
+            return Ok(false);
+        }
+
+        let rows = self.query(
+            format!("UPDATE urls SET pinned = ?2 {};", matching),
+            &[Value::String(key.into()), Value::Integer(pinned as i64)],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(true)
+    }
+
+    /// Forget about `key`, every variant of it,
+    /// and every version kept of them.
+    ///
+    /// Returns the content paths they referred to,
+    /// so the content can be removed too.
+    pub fn delete(&mut self, key: &str) -> Result<Vec<String>, Error> {
+        // Variant keys are the URL followed by a fragment.
+        let matching = "
+            WHERE url = ?1
+            OR substr(url, 1, length(?1) + 1) = ?1 || '#'
+        ";
+
+        self.conn.execute("BEGIN;")?;
+        let transaction = Transaction::new(&self.conn, self.commit_retries);
+
+        let paths = self
+            .query(
+                format!(
+                    "
+                    SELECT path FROM urls {0}
+                    UNION SELECT path FROM versions {0};
+                    ",
+                    matching,
+                ),
+                &[Value::String(key.into())],
+            )?
+            .filter_map(|row| match row.into_iter().next().unwrap() {
+                Value::String(path) => Some(path),
+                other => {
+                    warn!("path contained weird type: {:?}", other);
+                    None
+                }
+            })
+            .collect();
+
+        for table in &["urls", "versions"] {
+            let rows = self.query(
+                format!("DELETE FROM {} {};", table, matching),
+                &[Value::String(key.into())],
+            )?;
+
+            // Exhaust the row iterator to ensure the query is executed.
+            for _ in rows {}
+        }
+
+        self.conn.execute(ORPHANED_ANNOTATIONS_SQL)?;
+
+        transaction.commit()?;
+
+        Ok(paths)
+    }
 
+    /// Forget about everything in the cache.
+    ///
+    /// Returns the content paths that were referred to,
+    /// so the content can be removed too.
+    pub fn clear(&mut self) -> Result<Vec<String>, Error> {
+        self.conn.execute("BEGIN;")?;
+        let transaction = Transaction::new(&self.conn, self.commit_retries);
+
+        let paths = self
+            .query(
+                "SELECT path FROM urls UNION SELECT path FROM versions;",
+                &[],
+            )?
+            .filter_map(|row| match row.into_iter().next().unwrap() {
+                Value::String(path) => Some(path),
+                other => {
+                    warn!("path contained weird type: {:?}", other);
+                    None
+                }
+            })
+            .collect();
+
+        for table in &["urls", "versions", "partials", "annotations"] {
+            let rows = self.query(format!("DELETE FROM {};", table), &[])?;
+
+            // Exhaust the row iterator to ensure the query is executed.
+            for _ in rows {}
+        }
+
+        transaction.commit()?;
+
+        Ok(paths)
+    }
+
+    /// Record that `key`, and every variant of it, came from `source`.
+    pub fn set_source(&mut self, key: &str, source: &str) -> Result<(), Error> {
         let rows = self.query(
             "
-            INSERT OR REPLACE INTO urls
-                (url, path, last_modified, etag)
-            VALUES
-                (?1, ?2, ?3, ?4);
+            UPDATE urls SET source = ?2
+            WHERE url = ?1
+            OR substr(url, 1, length(?1) + 1) = ?1 || '#';
+            ",
+            &[Value::String(key.into()), Value::String(source.into())],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Record whether the content at `path` needs to be revalidated.
+    pub fn set_dirty(&mut self, path: &str, dirty: bool) -> Result<(), Error> {
+        let rows = self.query(
+            "UPDATE urls SET dirty = ?2 WHERE path = ?1;",
+            &[Value::String(path.into()), Value::Integer(dirty as i64)],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Record that the content at `path` was used at the given time.
+    pub fn touch(
+        &mut self,
+        path: &str,
+        at: time::SystemTime,
+    ) -> Result<(), Error> {
+        let rows = self.query(
+            "UPDATE urls SET last_accessed = ?2 WHERE path = ?1;",
+            &[Value::String(path.into()), time_value(Some(at))],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Forget about the URL stored under exactly `key`,
+    /// leaving its variants and its kept versions alone.
+    pub fn delete_url(&mut self, key: &str) -> Result<(), Error> {
+        let rows = self.query(
+            "DELETE FROM urls WHERE url = ?1;",
+            &[Value::String(key.into())],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        self.conn.execute(ORPHANED_ANNOTATIONS_SQL)?;
+
+        Ok(())
+    }
+
+    /// Return whether any cached URL or kept version
+    /// still refers to the content at `path`.
+    pub fn path_in_use(&self, path: &str) -> Result<bool, Error> {
+        let mut rows = self.query(
+            "
+            SELECT EXISTS (SELECT 1 FROM urls WHERE path = ?1)
+                OR EXISTS (SELECT 1 FROM versions WHERE path = ?1);
             ",
+            &[Value::String(path.into())],
+        )?;
+
+        match rows.next().map(|row| row.into_iter().next().unwrap()) {
+            Some(Value::Integer(in_use)) => Ok(in_use != 0),
+            other => {
+                warn!("EXISTS returned weird type: {:?}", other);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Forget about every URL and version whose content is at `path`.
+    pub fn delete_path(&mut self, path: &str) -> Result<(), Error> {
+        for table in &["urls", "versions"] {
+            let rows = self.query(
+                format!("DELETE FROM {} WHERE path = ?1;", table),
+                &[Value::String(path.into())],
+            )?;
+
+            // Exhaust the row iterator to ensure the query is executed.
+            for _ in rows {}
+        }
+
+        self.conn.execute(ORPHANED_ANNOTATIONS_SQL)?;
+
+        Ok(())
+    }
+
+    /// Attach `value` to `key` under the name `name`,
+    /// replacing any value it already had.
+    ///
+    /// Returns false, and does nothing, if `key` is not cached.
+    pub fn set_annotation(
+        &mut self,
+        key: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<bool, Error> {
+        let rows: Vec<_> = self
+            .query(
+                "
+                SELECT COUNT(*) FROM urls
+                WHERE url = ?1
+                OR substr(url, 1, length(?1) + 1) = ?1 || '#';
+                ",
+                &[Value::String(key.into())],
+            )?
+            .collect();
+        if let Value::Integer(0) = rows[0][0] {
+            return Ok(false);
+        }
+
+        let rows = self.query(
+            "INSERT OR REPLACE INTO annotations (url, key, value)
+                VALUES (?1, ?2, ?3);",
             &[
-                Value::String(url.as_str().into()),
-                Value::String(record.path),
-                record
-                    .last_modified
-                    .map(Value::String)
-                    .unwrap_or(Value::Null),
-                record.etag.map(Value::String).unwrap_or(Value::Null),
+                Value::String(key.into()),
+                Value::String(name.into()),
+                Value::String(value.into()),
             ],
         )?;
 
         // Exhaust the row iterator to ensure the query is executed.
         for _ in rows {}
 
-        Ok(res)
+        Ok(true)
+    }
+
+    /// Return every annotation attached to `key`, by name.
+    pub fn annotations(
+        &self,
+        key: &str,
+    ) -> Result<HashMap<String, String>, Error> {
+        let rows = self.query(
+            "SELECT key, value FROM annotations WHERE url = ?1;",
+            &[Value::String(key.into())],
+        )?;
+
+        Ok(rows
+            .filter_map(|row| {
+                let mut cols = row.into_iter();
+
+                match (cols.next().unwrap(), cols.next().unwrap()) {
+                    (Value::String(name), Value::String(value)) => {
+                        Some((name, value))
+                    }
+                    other => {
+                        warn!("annotations contained weird types: {:?}", other);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Record that the download of `url` was interrupted.
+    pub fn set_partial(
+        &mut self,
+        url: &str,
+        partial: &Partial,
+    ) -> Result<(), Error> {
+        let rows = self.query(
+            "INSERT OR REPLACE INTO partials (url, size, validator)
+                VALUES (?1, ?2, ?3);",
+            &[
+                Value::String(url.into()),
+                Value::Integer(partial.size as i64),
+                Value::String(partial.validator.clone()),
+            ],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Return the interrupted download of `url`, if any.
+    pub fn get_partial(&self, url: &str) -> Result<Option<Partial>, Error> {
+        let mut rows = self.query(
+            "SELECT size, validator FROM partials WHERE url = ?1;",
+            &[Value::String(url.into())],
+        )?;
+
+        Ok(rows.next().and_then(|row| {
+            let mut cols = row.into_iter();
+
+            match (cols.next().unwrap(), cols.next().unwrap()) {
+                (Value::Integer(size), Value::String(validator)) => {
+                    Some(Partial {
+                        size: size as u64,
+                        validator,
+                    })
+                }
+                other => {
+                    warn!("partials contained weird types: {:?}", other);
+                    None
+                }
+            }
+        }))
+    }
+
+    /// Forget the interrupted download of `url`.
+    pub fn delete_partial(&mut self, url: &str) -> Result<(), Error> {
+        let rows = self.query(
+            "DELETE FROM partials WHERE url = ?1;",
+            &[Value::String(url.into())],
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Record that `record` is the newest version of a key's content.
+    ///
+    /// Keys are usually URLs, but see [`variant_key`].
+    pub fn add_version(
+        &mut self,
+        key: &str,
+        record: CacheRecord,
+    ) -> Result<(), Error> {
+        let mut values = vec![Value::String(key.into())];
+        values.extend(record.into_values());
+
+        let placeholders: Vec<String> =
+            (1..=values.len()).map(|i| format!("?{}", i)).collect();
+
+        let rows = self.query(
+            format!(
+                "INSERT INTO versions (url, {}) VALUES ({});",
+                RECORD_COLUMNS,
+                placeholders.join(", "),
+            ),
+            &values,
+        )?;
+
+        // Exhaust the row iterator to ensure the query is executed.
+        for _ in rows {}
+
+        Ok(())
+    }
+
+    /// Return every recorded version of a key's content, newest first.
+    ///
+    /// Keys are usually URLs, but see [`variant_key`].
+    pub fn versions(&self, key: &str) -> Result<Vec<CacheRecord>, Error> {
+        let rows = self.query(
+            format!(
+                "
+                SELECT {}
+                FROM versions
+                WHERE url = ?1
+                ORDER BY rowid DESC;
+                ",
+                RECORD_COLUMNS,
+            ),
+            &[Value::String(key.into())],
+        )?;
+
+        rows.map(CacheRecord::from_row).collect()
+    }
+
+    /// Forget all but the newest `keep` versions of a key's content.
+    ///
+    /// Returns the content paths of the forgotten versions.
+    pub fn prune_versions(
+        &mut self,
+        key: &str,
+        keep: usize,
+    ) -> Result<Vec<String>, Error> {
+        let rows = self.query(
+            "
+            SELECT rowid, path
+            FROM versions
+            WHERE url = ?1
+            ORDER BY rowid DESC
+            LIMIT -1 OFFSET ?2;
+            ",
+            &[Value::String(key.into()), Value::Integer(keep as i64)],
+        )?;
+
+        let mut pruned = vec![];
+        for row in rows.collect::<Vec<_>>() {
+            let mut cols = row.into_iter();
+
+            if let (Some(Value::Integer(rowid)), Some(Value::String(path))) =
+                (cols.next(), cols.next())
+            {
+                let rows = self.query(
+                    "DELETE FROM versions WHERE rowid = ?1;",
+                    &[Value::Integer(rowid)],
+                )?;
+                for _ in rows {}
+
+                pruned.push(path);
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Return the key, content path and recorded body size
+    /// of every cached URL,
+    /// and whether the URL is pinned.
+    ///
+    /// The least recently used content is listed first.
+    pub fn sizes(&self) -> Result<Vec<EntrySize>, Error> {
+        let rows = self.query(
+            "SELECT url, path, size, pinned FROM urls ORDER BY last_accessed;",
+            &[],
+        )?;
+
+        Ok(rows
+            .filter_map(|row| {
+                let mut cols = row.into_iter();
+
+                match (cols.next().unwrap(), cols.next().unwrap()) {
+                    (Value::String(key), Value::String(path)) => Some((
+                        key,
+                        path,
+                        optional_size(cols.next().unwrap()),
+                        optional_integer(cols.next().unwrap(), "pinned")
+                            .is_some_and(|i| i != 0),
+                    )),
+                    other => {
+                        warn!("url or path contained weird type: {:?}", other);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Return the key and content path of every URL that came from `source`.
+    pub fn keys_from_source(
+        &self,
+        source: &str,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let rows = self.query(
+            "SELECT url, path FROM urls WHERE source = ?1;",
+            &[Value::String(source.into())],
+        )?;
+
+        Ok(rows.filter_map(key_and_path).collect())
+    }
+
+    /// Return the key and content path
+    /// of every URL downloaded before `cutoff`.
+    ///
+    /// URLs whose download time was never recorded,
+    /// and pinned URLs, are not included.
+    pub fn keys_downloaded_before(
+        &self,
+        cutoff: time::SystemTime,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let rows = self.query(
+            "SELECT url, path FROM urls
+                WHERE downloaded_at < ?1 AND pinned IS NOT 1;",
+            &[time_value(Some(cutoff))],
+        )?;
+
+        Ok(rows.filter_map(key_and_path).collect())
+    }
+}
+
+/// Interpret a row holding a key and a content path.
+fn key_and_path(row: Vec<Value>) -> Option<(String, String)> {
+    let mut cols = row.into_iter();
+
+    match (cols.next()?, cols.next()?) {
+        (Value::String(key), Value::String(path)) => Some((key, path)),
+        other => {
+            warn!("url or path contained weird type: {:?}", other);
+            None
+        }
+    }
+}
+
+/// Interpret a value from a nullable TEXT column.
+fn optional_text(value: Value, column: &str) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Null => None,
+        other => {
+            warn!("{} contained weird type: {:?}", column, other);
+            None
+        }
+    }
+}
+
+/// Interpret a value from a nullable BLOB column.
+fn optional_blob(value: Value, column: &str) -> Option<Vec<u8>> {
+    match value {
+        Value::Binary(b) => Some(b),
+        Value::Null => None,
+        other => {
+            warn!("{} contained weird type: {:?}", column, other);
+            None
+        }
+    }
+}
+
+/// Interpret a value from a nullable INTEGER column.
+fn optional_integer(value: Value, column: &str) -> Option<i64> {
+    match value {
+        Value::Integer(i) => Some(i),
+        Value::Null => None,
+        other => {
+            warn!("{} contained weird type: {:?}", column, other);
+            None
+        }
     }
 }
 
+/// Interpret a value from the `size` column.
+fn optional_size(value: Value) -> Option<u64> {
+    optional_integer(value, "size").map(|i| i as u64)
+}
+
+/// Interpret a value from a column of timestamps.
+///
+/// Timestamps are stored as whole seconds since the Unix epoch.
+fn optional_time(value: Value, column: &str) -> Option<time::SystemTime> {
+    optional_integer(value, column)
+        .map(|i| time::UNIX_EPOCH + time::Duration::from_secs(i as u64))
+}
+
+fn text_value(value: Option<String>) -> Value {
+    value.map(Value::String).unwrap_or(Value::Null)
+}
+
+fn integer_value(value: Option<i64>) -> Value {
+    value.map(Value::Integer).unwrap_or(Value::Null)
+}
+
+fn time_value(value: Option<time::SystemTime>) -> Value {
+    integer_value(value.map(|t| {
+        t.duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    }))
+}
+
 impl fmt::Debug for CacheDB {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "CacheDB {{path: {:?}}}", self.path)
@@ -276,6 +1461,14 @@ mod tests {
 
     extern crate tempdir;
 
+    /// The rows listing the tables of a new database.
+    fn expected_tables() -> Vec<Vec<Value>> {
+        ["annotations", "partials", "urls", "versions"]
+            .iter()
+            .map(|name| vec![Value::String((*name).into())])
+            .collect()
+    }
+
     #[test]
     fn create_fresh_db() {
         let db =
@@ -283,13 +1476,13 @@ mod tests {
 
         let rows: Vec<_> = db
             .query(
-                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
+                "SELECT name FROM sqlite_master WHERE TYPE = ?1 ORDER BY name",
                 &[Value::String("table".into())],
             )
             .unwrap()
             .collect();
 
-        assert_eq!(rows, vec![vec![Value::String("urls".into())]]);
+        assert_eq!(rows, expected_tables());
     }
 
     #[test]
@@ -300,22 +1493,22 @@ mod tests {
         let db1 = super::CacheDB::new(db_path.clone()).unwrap();
         let rows: Vec<_> = db1
             .query(
-                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
+                "SELECT name FROM sqlite_master WHERE TYPE = ?1 ORDER BY name",
                 &[Value::String("table".into())],
             )
             .unwrap()
             .collect();
-        assert_eq!(rows, vec![vec![Value::String("urls".into())]]);
+        assert_eq!(rows, expected_tables());
 
         let db2 = super::CacheDB::new(db_path).unwrap();
         let rows: Vec<_> = db2
             .query(
-                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
+                "SELECT name FROM sqlite_master WHERE TYPE = ?1 ORDER BY name",
                 &[Value::String("table".into())],
             )
             .unwrap()
             .collect();
-        assert_eq!(rows, vec![vec![Value::String("urls".into())]]);
+        assert_eq!(rows, expected_tables());
     }
 
     #[test]
@@ -353,6 +1546,7 @@ mod tests {
                 path: "path/to/data".into(),
                 last_modified: None,
                 etag: None,
+                ..Default::default()
             },
         )
         .unwrap()
@@ -381,6 +1575,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: None,
             etag: None,
+            ..Default::default()
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -403,6 +1598,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".into()),
             etag: Some("some-etag".into()),
+            ..Default::default()
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -484,6 +1680,7 @@ mod tests {
                 // treat it as NULL.
                 last_modified: None,
                 etag: None,
+                ..Default::default()
             }
         );
     }
@@ -497,6 +1694,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: None,
             etag: None,
+            ..Default::default()
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -517,6 +1715,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: None,
             etag: None,
+            ..Default::default()
         };
 
         let mut db =
@@ -545,6 +1744,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".into()),
             etag: Some("some-etag".into()),
+            ..Default::default()
         };
 
         let mut db =
@@ -568,6 +1768,7 @@ mod tests {
             path: "path/to/data".into(),
             last_modified: None,
             etag: None,
+            ..Default::default()
         };
 
         let mut db =
@@ -596,12 +1797,14 @@ mod tests {
             path: "path/to/data/one".into(),
             last_modified: None,
             etag: Some("one".into()),
+            ..Default::default()
         };
 
         let record_two = super::CacheRecord {
             path: "path/to/data/two".into(),
             last_modified: None,
             etag: Some("two".into()),
+            ..Default::default()
         };
 
         let mut db =
@@ -632,12 +1835,14 @@ mod tests {
             path: "path/to/data/one".into(),
             last_modified: None,
             etag: Some("one".into()),
+            ..Default::default()
         };
 
         let record_two = super::CacheRecord {
             path: "path/to/data/two".into(),
             last_modified: None,
             etag: Some("two".into()),
+            ..Default::default()
         };
 
         let mut db =
@@ -733,4 +1938,124 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn pins_survive_new_records() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let record = |path: &str| super::CacheRecord {
+            path: path.into(),
+            ..Default::default()
+        };
+
+        assert!(!db.set_pinned(url.as_str(), true).unwrap());
+
+        db.set(url.clone(), record("one"))
+            .unwrap()
+            .commit()
+            .unwrap();
+        db.set_key(
+            &super::variant_key(url.as_str(), "image/webp"),
+            record("two"),
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+        assert!(db.set_pinned(url.as_str(), true).unwrap());
+
+        db.set(url.clone(), record("three"))
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        let mut sizes = db.sizes().unwrap();
+        sizes.sort();
+        assert_eq!(
+            sizes,
+            vec![
+                (url.as_str().into(), "three".into(), None, true),
+                (
+                    super::variant_key(url.as_str(), "image/webp"),
+                    "two".into(),
+                    None,
+                    true,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn paths_stay_in_use_until_nothing_refers_to_them() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+        let record = |path: &str| super::CacheRecord {
+            path: path.into(),
+            ..Default::default()
+        };
+
+        db.set_key("http://example.com/a", record("shared"))
+            .unwrap()
+            .commit()
+            .unwrap();
+        db.set_key("http://example.com/b", record("shared"))
+            .unwrap()
+            .commit()
+            .unwrap();
+        db.add_version("http://example.com/c", record("kept"))
+            .unwrap();
+        assert!(db.path_in_use("shared").unwrap());
+        assert!(db.path_in_use("kept").unwrap());
+        assert!(!db.path_in_use("other").unwrap());
+
+        db.delete_url("http://example.com/a").unwrap();
+        assert!(db.get_key("http://example.com/a").unwrap().is_none());
+        assert!(db.path_in_use("shared").unwrap());
+
+        db.delete_url("http://example.com/b").unwrap();
+        assert!(!db.path_in_use("shared").unwrap());
+        assert!(db.path_in_use("kept").unwrap());
+    }
+
+    #[test]
+    fn busy_commits_are_retried() {
+        let busy = || sqlite::Error {
+            code: Some(SQLITE_BUSY),
+            message: Some("database is locked".into()),
+        };
+
+        // Busy the first time, fine the second.
+        let mut attempts = 0;
+        let res = super::retry_if_busy(3, || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(busy())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(attempts, 2);
+
+        // We give up eventually.
+        let mut attempts = 0;
+        let res = super::retry_if_busy(2, || {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 3);
+
+        // Other errors aren't retried.
+        let mut attempts = 0;
+        let res = super::retry_if_busy(3, || {
+            attempts += 1;
+            Err(sqlite::Error {
+                code: Some(1),
+                message: None,
+            })
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+    }
 }
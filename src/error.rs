@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use http::header::HeaderName;
 use reqwest::Url;
 use thiserror::Error;
@@ -16,8 +18,49 @@ pub enum Error {
     Database(#[from] sqlite::Error),
     #[error("path had wrong type: {}", _0)]
     WrongPathType(String),
+    #[error("cache in {:?} has an incompatible layout: {}", _0, _1)]
+    IncompatibleLayout(PathBuf, String),
+    #[error("cache directory is not writable: {:?}", _0)]
+    ReadOnlyCache(PathBuf),
     #[error("URL not found in cache: {:?}", _0)]
     URLNotFound(Url),
+    #[error("cache is offline and does not have {}", _0)]
+    OfflineAndUncached(Url),
+    #[error("version {} of URL not found in cache: {:?}", _1, _0)]
+    VersionNotFound(Url, usize),
+    #[error("host not allowed: {}", _0)]
+    HostNotAllowed(String),
+    #[error("not enough free space to cache data in {:?}", _0)]
+    InsufficientSpace(PathBuf),
+    #[error("server returned error status {}", _0)]
+    ErrorStatus(reqwest::StatusCode),
+    #[error("server returned error status {}: {}", status, body)]
+    ServerError {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("too many redirects fetching {}", _0)]
+    TooManyRedirects(Url),
+    #[error("certificate for {} does not match its pin", _0)]
+    CertificatePinMismatch(String),
+    #[error("content of {} does not match its checksum", _0)]
+    ChecksumMismatch(Url),
+    #[error("content of {} was rejected by the content validator", _0)]
+    ContentValidationFailed(Url),
+    #[error("invalid manifest: {}", _0)]
+    InvalidManifest(String),
+    #[error("download of {} was {} bytes, expected {}", _0, _2, _1)]
+    ContentLengthMismatch(Url, u64, u64),
+    #[error("download of {} timed out after {} bytes", _0, _1)]
+    DownloadInterrupted(Url, u64),
+    #[error("rate limited by the server, retry after {:?}", retry_after)]
+    RateLimited {
+        retry_after: Option<std::time::Duration>,
+    },
+    #[error("bandwidth budget exceeded fetching {}", _0)]
+    BandwidthBudgetExceeded(Url),
+    #[error("more than {} file handles are open", _0)]
+    TooManyOpenHandles(usize),
     #[error("attempted to set cache-related header {}", _0)]
     DuplicateHeader(HeaderName),
     #[cfg_attr(test, error("fake error"))]
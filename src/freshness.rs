@@ -0,0 +1,261 @@
+//! Working out how long a response may be used without revalidation.
+use std::time;
+
+use reqwest::header as rh;
+use reqwest::header::HeaderMap;
+
+/// Return the directives of every Cache-Control header in `headers`.
+///
+/// Directive names are case-insensitive, so they are returned lowercased.
+fn cache_control_directives(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(rh::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .filter(|directive| !directive.is_empty())
+        .collect()
+}
+
+/// Return the freshness lifetime given by a Cache-Control `max-age`
+/// directive in `headers`, if any.
+fn max_age(headers: &HeaderMap) -> Option<time::Duration> {
+    cache_control_directives(headers)
+        .iter()
+        .filter_map(|directive| directive.strip_prefix("max-age="))
+        .filter_map(|seconds| seconds.trim_matches('"').parse().ok())
+        .map(time::Duration::from_secs)
+        .next()
+}
+
+/// Return how long the response spent in other caches
+/// before it reached us, according to its Age header.
+fn age(headers: &HeaderMap) -> time::Duration {
+    headers
+        .get(rh::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(time::Duration::from_secs)
+        .unwrap_or_default()
+}
+
+/// Return the time given by the header `name` in `headers`, if any.
+fn http_date(
+    headers: &HeaderMap,
+    name: rh::HeaderName,
+) -> Option<time::SystemTime> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// Return the time at which a response with the given headers,
+/// received at `now`, becomes stale.
+///
+/// If the response has both Expires and Date headers,
+/// its lifetime is the difference between them,
+/// so it doesn't matter whether the server's clock agrees with ours.
+/// A lifetime that would be negative is treated as zero.
+///
+/// A response with `Cache-Control: no-cache`,
+/// or with an Expires header that isn't a valid date,
+/// is stale as soon as it's received.
+///
+/// Returns `None` if the response does not say how long it stays fresh.
+pub fn deadline(
+    headers: &HeaderMap,
+    now: time::SystemTime,
+) -> Option<time::SystemTime> {
+    if cache_control_directives(headers)
+        .iter()
+        .any(|directive| directive == "no-cache")
+    {
+        return Some(now);
+    }
+
+    let expires = http_date(headers, rh::EXPIRES);
+
+    let lifetime =
+        match (max_age(headers), expires, http_date(headers, rh::DATE)) {
+            (Some(lifetime), _, _) => lifetime,
+            (None, None, _) if headers.contains_key(rh::EXPIRES) => {
+                return Some(now)
+            }
+            (None, Some(expires), Some(date)) => {
+                expires.duration_since(date).unwrap_or_default()
+            }
+            (None, expires, None) => return expires,
+            (None, None, Some(_)) => return None,
+        };
+
+    Some(now + lifetime.checked_sub(age(headers)).unwrap_or_default())
+}
+
+/// Guess when a response with the given headers,
+/// received at `now`, becomes stale,
+/// for responses that don't say.
+///
+/// As suggested by RFC 7234, section 4.2.2,
+/// a response that was last modified long ago
+/// is unlikely to change soon,
+/// so it's assumed to stay fresh for `fraction`
+/// of the time since it was last modified.
+///
+/// Returns `None` if the response has no Last-Modified header.
+pub fn heuristic_deadline(
+    headers: &HeaderMap,
+    now: time::SystemTime,
+    fraction: f64,
+) -> Option<time::SystemTime> {
+    let last_modified = http_date(headers, rh::LAST_MODIFIED)?;
+    let date = http_date(headers, rh::DATE).unwrap_or(now);
+
+    let unchanged_for = date.duration_since(last_modified).unwrap_or_default();
+
+    Some(now + unchanged_for.mul_f64(fraction.max(0.0)))
+}
+
+/// Return how far ahead of `now` the Date header in `headers` is,
+/// or `None` if it isn't ahead (or there's no Date header).
+///
+/// A server whose clock is far ahead of ours
+/// may send Expires headers that look much further away than intended.
+pub fn clock_skew(
+    headers: &HeaderMap,
+    now: time::SystemTime,
+) -> Option<time::Duration> {
+    http_date(headers, rh::DATE)?
+        .duration_since(now)
+        .ok()
+        .filter(|skew| *skew > time::Duration::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use reqwest::header as rh;
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn max_age_is_relative_to_now() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            rh::CACHE_CONTROL,
+            HeaderValue::from_static("public, Max-Age=60"),
+        );
+
+        assert_eq!(
+            super::deadline(&headers, now),
+            Some(now + time::Duration::from_secs(60)),
+        );
+    }
+
+    #[test]
+    fn max_age_takes_priority_over_expires() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            rh::EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        headers
+            .append(rh::CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        headers.append(rh::AGE, HeaderValue::from_static("20"));
+
+        assert_eq!(
+            super::deadline(&headers, now),
+            Some(now + time::Duration::from_secs(40)),
+        );
+    }
+
+    #[test]
+    fn expires_is_absolute() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            rh::EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:01:00 GMT"),
+        );
+
+        assert_eq!(
+            super::deadline(&headers, now),
+            Some(time::UNIX_EPOCH + time::Duration::from_secs(60)),
+        );
+    }
+
+    #[test]
+    fn malformed_expires_is_already_stale() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        let mut headers = HeaderMap::new();
+        headers.append(rh::EXPIRES, HeaderValue::from_static("0"));
+
+        assert_eq!(super::deadline(&headers, now), Some(now));
+    }
+
+    #[test]
+    fn no_cache_overrides_expires() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            rh::EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 2037 00:00:00 GMT"),
+        );
+        headers.append(
+            rh::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=3600, No-Cache"),
+        );
+
+        assert_eq!(super::deadline(&headers, now), Some(now));
+    }
+
+    #[test]
+    fn server_clock_ahead_of_ours() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+
+        // The server thinks it's an hour later than we do.
+        let mut headers = HeaderMap::new();
+        headers.append(
+            rh::DATE,
+            HeaderValue::from_static("Thu, 01 Jan 1970 01:16:40 GMT"),
+        );
+        headers.append(
+            rh::EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 01:17:40 GMT"),
+        );
+        headers.append(rh::AGE, HeaderValue::from_static("-30"));
+
+        // The response lasts a minute from when we got it,
+        // and the nonsensical negative age counts as zero.
+        assert_eq!(
+            super::deadline(&headers, now),
+            Some(now + time::Duration::from_secs(60)),
+        );
+        assert_eq!(
+            super::clock_skew(&headers, now),
+            Some(time::Duration::from_secs(3600)),
+        );
+
+        // An Expires header before the Date header is already stale.
+        headers.insert(
+            rh::EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        assert_eq!(super::deadline(&headers, now), Some(now));
+
+        // If the server's clock agrees with ours, there's no skew.
+        headers.insert(
+            rh::DATE,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:16:40 GMT"),
+        );
+        assert_eq!(super::clock_skew(&headers, now), None);
+    }
+}
@@ -0,0 +1,45 @@
+//! Deciding which cache entry the content of a URL is stored under.
+
+/// Chooses the key under which the content of a URL is cached.
+///
+/// URLs with the same key share one cache entry,
+/// so a policy can make the cache treat URLs that differ
+/// in unimportant ways as the same resource.
+///
+/// Keys should look like absolute URLs without fragments.
+/// Methods that work on every cached entry,
+/// like [`Cache::refresh_expiring`](crate::Cache::refresh_expiring()),
+/// skip keys that aren't URLs,
+/// and fragments are used to store variants of an entry.
+pub trait KeyPolicy {
+    /// Return the key for `url`.
+    fn key(&self, url: &reqwest::Url) -> String;
+}
+
+/// The key policy caches use unless configured otherwise.
+///
+/// Each URL is its own key, ignoring any fragment,
+/// since fragments are never sent to the server.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultKeyPolicy;
+
+impl KeyPolicy for DefaultKeyPolicy {
+    fn key(&self, url: &reqwest::Url) -> String {
+        let mut url = url.clone();
+        url.set_fragment(None);
+
+        url.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultKeyPolicy, KeyPolicy};
+
+    #[test]
+    fn default_policy_ignores_fragments() {
+        let url: reqwest::Url = "http://example.com/a?b=c#d".parse().unwrap();
+
+        assert_eq!(DefaultKeyPolicy.key(&url), "http://example.com/a?b=c");
+    }
+}
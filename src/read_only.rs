@@ -0,0 +1,197 @@
+//! A view of a cache that can only read from it.
+use std::fs;
+use std::path;
+
+use reqwest::blocking::Request;
+
+use crate::error::Error;
+use crate::reqwest_mock;
+use crate::{Cache, CacheEntryInfo, ResponseMeta};
+
+/// A view of a local cache of HTTP resources
+/// that can read cached content, but never changes the cache.
+///
+/// It never contacts a server,
+/// and only has methods that read from the cache,
+/// so it can be handed to code that should only consume the cache,
+/// while a [`Cache`] in another thread or process keeps it up to date.
+/// The cache metadata is opened read-only as well,
+/// so nothing can be changed through it by accident.
+#[derive(Debug)]
+pub struct ReadOnlyCache {
+    inner: Cache<Offline>,
+}
+
+/// A client for caches that must never contact a server.
+#[derive(Debug)]
+pub(crate) struct Offline;
+
+impl reqwest_mock::Client for Offline {
+    type Response = reqwest::blocking::Response;
+
+    fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+        Err(Error::URLNotFound(request.url().clone()))
+    }
+}
+
+impl ReadOnlyCache {
+    /// Returns a ReadOnlyCache that reads data cached in `root`.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::open_read_only`] might.
+    pub fn open(root: path::PathBuf) -> Result<ReadOnlyCache, Error> {
+        Ok(ReadOnlyCache {
+            inner: Cache::open_read_only(root, Offline)?,
+        })
+    }
+
+    /// Returns a ReadOnlyCache that reads the data cached by `cache`,
+    /// finding it with the same key policy.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::open_read_only`] might.
+    pub fn from_cache<C: reqwest_mock::Client>(
+        cache: &Cache<C>,
+    ) -> Result<ReadOnlyCache, Error> {
+        let mut res = ReadOnlyCache::open(cache.root.clone())?;
+        res.inner.key_policy = cache.key_policy.clone();
+        res.inner.trailing_slash_policy = cache.trailing_slash_policy;
+
+        Ok(res)
+    }
+
+    /// Open the cached content of `url`,
+    /// or return `None` if it isn't cached.
+    ///
+    /// Cached content is returned even if it's stale.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error
+    /// if the cache metadata is corrupt,
+    /// or if the cached content cannot be opened.
+    pub fn get_if_cached(
+        &self,
+        url: &reqwest::Url,
+    ) -> Result<Option<fs::File>, Error> {
+        self.inner.open_cached(url)
+    }
+
+    /// Return the status and headers of the response
+    /// whose content is cached for `url`,
+    /// or `None` if it isn't cached.
+    ///
+    /// See [`Cache::get_with_meta`].
+    pub fn metadata(&self, url: &reqwest::Url) -> Option<ResponseMeta> {
+        self.inner
+            .usable_record(url, &[])
+            .map(|record| ResponseMeta::from_record(&record))
+    }
+
+    /// Describe every cached URL, one at a time.
+    ///
+    /// See [`Cache::iter_entries`].
+    pub fn iter_entries(
+        &self,
+    ) -> impl Iterator<Item = Result<CacheEntryInfo, Error>> + '_ {
+        self.inner.iter_entries()
+    }
+
+    /// Return the path of the file holding the cached content of `url`,
+    /// or `None` if it isn't cached.
+    ///
+    /// Content that is compressed,
+    /// or stored in the cache metadata instead of in a file of its own,
+    /// has no such path;
+    /// use [`get_if_cached`](Self::get_if_cached()) to read it.
+    pub fn path_of(&self, url: &reqwest::Url) -> Option<path::PathBuf> {
+        self.inner
+            .usable_record(url, &[])
+            .filter(|record| !record.compressed && record.body.is_none())
+            .map(|record| self.inner.root.join(record.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+
+    use reqwest::header::HeaderMap;
+
+    use super::ReadOnlyCache;
+    use crate::reqwest_mock::tests as rmt;
+    use crate::Cache;
+
+    #[test]
+    fn read_only_cache_reads_what_a_cache_wrote() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let missing: reqwest::Url =
+            "http://example.com/missing".parse().unwrap();
+
+        let later: reqwest::Url = "http://example.com/later".parse().unwrap();
+
+        let mut server = rmt::FakeServer::new();
+        for url in &[&url, &later] {
+            server.add(
+                (*url).clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut c = Cache::new(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            server,
+        )
+        .unwrap();
+        c.get(url.clone()).unwrap();
+
+        let reader = ReadOnlyCache::from_cache(&c).unwrap();
+
+        let mut buf = vec![];
+        reader
+            .get_if_cached(&url)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(reader.get_if_cached(&missing).unwrap().is_none());
+
+        let meta = reader.metadata(&url).unwrap();
+        assert_eq!(meta.status, Some(reqwest::StatusCode::OK));
+        assert_eq!(
+            std::fs::read(reader.path_of(&url).unwrap()).unwrap(),
+            b"hello"
+        );
+        assert_eq!(reader.iter_entries().count(), 1);
+
+        // The cache metadata can't be changed through the view.
+        assert!(reader
+            .inner
+            .db
+            .with_connection(|conn| conn.execute("DELETE FROM urls;"))
+            .is_err());
+
+        // The writer can keep using the cache while the view is open,
+        // and the view sees what it adds.
+        c.get(later.clone()).unwrap();
+        assert!(reader.get_if_cached(&later).unwrap().is_some());
+    }
+}
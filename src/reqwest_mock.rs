@@ -3,11 +3,28 @@
 //!
 //! You do not need to care about this module
 //! if you just want to use this crate.
+//!
+//! However, [`RecordingClient`] and [`ReplayClient`]
+//! can be used to test code that uses a cache
+//! without a live server.
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt;
+use std::fs;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync;
 
 use reqwest::blocking::Request;
-use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode, Url};
 
 use crate::error::Error;
 
@@ -26,6 +43,20 @@ where
 
     /// Return an error if the response's status is in the range 400-599.
     fn error_for_status(self) -> Result<Self, Error>;
+
+    /// Return the DER-encoded certificate the server presented
+    /// when this response was received, if known.
+    ///
+    /// Responses received over plain HTTP,
+    /// or from clients that don't report certificates,
+    /// return `None`.
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Return the URL this response came from,
+    /// after following any redirects.
+    fn url(&self) -> &reqwest::Url;
 }
 
 impl HttpResponse for reqwest::blocking::Response {
@@ -38,6 +69,9 @@ impl HttpResponse for reqwest::blocking::Response {
     fn error_for_status(self) -> Result<Self, Error> {
         Ok(self.error_for_status()?)
     }
+    fn url(&self) -> &reqwest::Url {
+        self.url()
+    }
 }
 
 /// Represents a thing that can send requests.
@@ -55,16 +89,355 @@ impl Client for reqwest::blocking::Client {
     type Response = reqwest::blocking::Response;
 
     fn execute(&self, request: Request) -> Result<Self::Response, Error> {
-        Ok(self.execute(request)?)
+        let url = request.url().clone();
+
+        self.execute(request).map_err(|err| {
+            // Redirect errors mean a redirect loop,
+            // or a chain of redirects longer than the client allows.
+            if err.is_redirect() {
+                Error::TooManyRedirects(url)
+            } else {
+                err.into()
+            }
+        })
+    }
+}
+
+impl<C: Client + ?Sized> Client for &C {
+    type Response = C::Response;
+
+    fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+        (**self).execute(request)
+    }
+}
+
+/// A future returned by [`AsyncClient`] and [`AsyncHttpResponse`].
+#[cfg(feature = "async")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Represents the result of sending an HTTP request asynchronously.
+///
+/// Modelled after `reqwest::Response`.
+#[cfg(feature = "async")]
+pub trait AsyncHttpResponse: fmt::Debug + Send
+where
+    Self: Sized,
+{
+    /// Obtain access to the headers of the response.
+    fn headers(&self) -> &reqwest::header::HeaderMap;
+
+    /// Obtain a copy of the response's status.
+    fn status(&self) -> StatusCode;
+
+    /// Return an error if the response's status is in the range 400-599.
+    fn error_for_status(self) -> Result<Self, Error>;
+
+    /// Return the URL this response came from,
+    /// after following any redirects.
+    fn url(&self) -> &reqwest::Url;
+
+    /// Return the next chunk of the response body,
+    /// or `None` once the whole body has been read.
+    fn chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, Error>>;
+}
+
+#[cfg(feature = "async")]
+impl AsyncHttpResponse for reqwest::Response {
+    fn headers(&self) -> &reqwest::header::HeaderMap {
+        self.headers()
+    }
+    fn status(&self) -> StatusCode {
+        self.status()
+    }
+    fn error_for_status(self) -> Result<Self, Error> {
+        Ok(self.error_for_status()?)
+    }
+    fn url(&self) -> &reqwest::Url {
+        self.url()
+    }
+    fn chunk(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            Ok(reqwest::Response::chunk(self)
+                .await?
+                .map(|chunk| chunk.to_vec()))
+        })
+    }
+}
+
+/// Represents a thing that can send requests asynchronously.
+///
+/// Modelled after `reqwest::Client`.
+#[cfg(feature = "async")]
+pub trait AsyncClient {
+    /// Sending a request produces this kind of response.
+    type Response: AsyncHttpResponse;
+
+    /// Send the given request and return the response (or an error).
+    fn execute(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'_, Result<Self::Response, Error>>;
+}
+
+#[cfg(feature = "async")]
+impl AsyncClient for reqwest::Client {
+    type Response = reqwest::Response;
+
+    fn execute(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'_, Result<Self::Response, Error>> {
+        let url = request.url().clone();
+        let response = reqwest::Client::execute(self, request);
+
+        Box::pin(async move {
+            response.await.map_err(|err| {
+                // As for the blocking client,
+                // redirect errors mean a redirect loop.
+                if err.is_redirect() {
+                    Error::TooManyRedirects(url)
+                } else {
+                    err.into()
+                }
+            })
+        })
+    }
+}
+
+/// A response recorded by [`RecordingClient`],
+/// or replayed by [`ReplayClient`].
+#[derive(Clone, Debug)]
+pub struct RecordedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: io::Cursor<Vec<u8>>,
+    url: Url,
+}
+
+impl HttpResponse for RecordedResponse {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+    fn error_for_status(self) -> Result<Self, Error> {
+        if self.status.is_client_error() || self.status.is_server_error() {
+            Err(Error::ErrorStatus(self.status))
+        } else {
+            Ok(self)
+        }
+    }
+    fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl Read for RecordedResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// A client that sends requests with another client,
+/// and records every response it gets to a file,
+/// to be played back later by [`ReplayClient`].
+///
+/// The file has one line per response,
+/// each of which is a JSON object
+/// with the request's `method` and `url`,
+/// and the response's `status`, `headers`,
+/// `body` (as hexadecimal),
+/// and `final_url` (after following any redirects).
+/// Requests that fail without a response are not recorded.
+#[derive(Debug)]
+pub struct RecordingClient<C: Client> {
+    inner: C,
+    file: sync::Mutex<fs::File>,
+}
+
+impl<C: Client> RecordingClient<C> {
+    /// Returns a client that sends requests with `inner`,
+    /// and records the responses in the file at `path`,
+    /// replacing anything already there.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method returns an error if the file cannot be created.
+    pub fn new(
+        inner: C,
+        path: &path::Path,
+    ) -> Result<RecordingClient<C>, Error> {
+        let file = sync::Mutex::new(fs::File::create(path)?);
+
+        Ok(RecordingClient { inner, file })
+    }
+}
+
+impl<C: Client> Client for RecordingClient<C> {
+    type Response = RecordedResponse;
+
+    fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+        let method = request.method().clone();
+        let url = request.url().clone();
+
+        let mut response = self.inner.execute(request)?;
+        let mut body = vec![];
+        response.read_to_end(&mut body)?;
+
+        let headers: Vec<(&str, String)> = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str(),
+                    String::from_utf8_lossy(value.as_bytes()).into(),
+                )
+            })
+            .collect();
+
+        let line = serde_json::json!({
+            "method": method.as_str(),
+            "url": url.as_str(),
+            "final_url": response.url().as_str(),
+            "status": response.status().as_u16(),
+            "headers": headers,
+            "body": crate::hex(&body),
+        });
+
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()?;
+
+        Ok(RecordedResponse {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: io::Cursor::new(body),
+            url: response.url().clone(),
+        })
     }
 }
 
+/// A client that never contacts a server,
+/// but answers requests with responses recorded by [`RecordingClient`].
+///
+/// Each request gets the next response recorded
+/// for the same method and URL,
+/// in the order they were recorded.
+/// Once they've all been used,
+/// the last one is repeated.
+#[derive(Debug)]
+pub struct ReplayClient {
+    responses: sync::Mutex<HashMap<(Method, Url), VecDeque<RecordedResponse>>>,
+}
+
+impl ReplayClient {
+    /// Returns a client that replays the responses
+    /// recorded in the file at `path`.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method returns an error
+    /// if the file cannot be read,
+    /// or was not written by [`RecordingClient`].
+    pub fn open(path: &path::Path) -> Result<ReplayClient, Error> {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+
+        for line in io::BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, response) = parse_recording(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid recording: {}", line),
+                )
+            })?;
+            responses.entry(key).or_default().push_back(response);
+        }
+
+        Ok(ReplayClient {
+            responses: sync::Mutex::new(responses),
+        })
+    }
+}
+
+impl Client for ReplayClient {
+    type Response = RecordedResponse;
+
+    fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+        let key = (request.method().clone(), request.url().clone());
+
+        let mut responses = self.responses.lock().unwrap();
+        let queue = match responses.get_mut(&key) {
+            Some(queue) => queue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no recorded response for {} {}", key.0, key.1),
+                )
+                .into())
+            }
+        };
+
+        Ok(if queue.len() > 1 {
+            queue.pop_front().unwrap()
+        } else {
+            queue[0].clone()
+        })
+    }
+}
+
+/// Parse a line written by [`RecordingClient`].
+fn parse_recording(line: &str) -> Option<((Method, Url), RecordedResponse)> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+
+    let method = value["method"].as_str()?.parse().ok()?;
+    let url: Url = value["url"].as_str()?.parse().ok()?;
+    let final_url = match value["final_url"].as_str() {
+        Some(final_url) => final_url.parse().ok()?,
+        None => url.clone(),
+    };
+    let status = StatusCode::from_u16(value["status"].as_u64()? as u16).ok()?;
+
+    let mut headers = HeaderMap::new();
+    for header in value["headers"].as_array()? {
+        let name: HeaderName = header[0].as_str()?.parse().ok()?;
+        let value = HeaderValue::from_str(header[1].as_str()?).ok()?;
+        headers.append(name, value);
+    }
+
+    let hex = value["body"].as_str()?;
+    let body = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some((
+        (method, url),
+        RecordedResponse {
+            status,
+            headers,
+            body: io::Cursor::new(body),
+            url: final_url,
+        },
+    ))
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::cell;
+    use std::collections::HashMap;
     use std::fmt;
     use std::io;
     use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time;
 
     use super::*;
 
@@ -87,11 +460,23 @@ pub mod tests {
         }
     }
 
-    #[derive(Clone, Debug)]
+    #[derive(Clone, Debug, Default)]
     pub struct FakeResponse {
         pub status: StatusCode,
         pub headers: reqwest::header::HeaderMap,
         pub body: io::Cursor<Vec<u8>>,
+        /// The URL the response came from.
+        ///
+        /// If `None`, fake clients fill in the URL of the request.
+        pub url: Option<reqwest::Url>,
+    }
+
+    impl FakeResponse {
+        /// Return this response as an answer to a request for `url`.
+        pub fn responding_to(mut self, url: &reqwest::Url) -> FakeResponse {
+            self.url.get_or_insert_with(|| url.clone());
+            self
+        }
     }
 
     impl super::HttpResponse for FakeResponse {
@@ -109,6 +494,11 @@ pub mod tests {
                 Err(FakeError.into())
             }
         }
+        fn url(&self) -> &reqwest::Url {
+            self.url
+                .as_ref()
+                .expect("response was not sent by a client")
+        }
     }
 
     impl Read for FakeResponse {
@@ -117,7 +507,36 @@ pub mod tests {
         }
     }
 
+    #[cfg(feature = "async")]
+    impl super::AsyncHttpResponse for FakeResponse {
+        fn headers(&self) -> &reqwest::header::HeaderMap {
+            &self.headers
+        }
+        fn status(&self) -> StatusCode {
+            self.status
+        }
+        fn error_for_status(self) -> Result<Self, Error> {
+            super::HttpResponse::error_for_status(self)
+        }
+        fn url(&self) -> &reqwest::Url {
+            super::HttpResponse::url(self)
+        }
+        fn chunk(
+            &mut self,
+        ) -> super::BoxFuture<'_, Result<Option<Vec<u8>>, Error>> {
+            let mut chunk = vec![];
+            let res = self
+                .body
+                .read_to_end(&mut chunk)
+                .map(|_| Some(chunk).filter(|chunk| !chunk.is_empty()))
+                .map_err(Error::from);
+
+            Box::pin(std::future::ready(res))
+        }
+    }
+
     pub struct FakeClient {
+        pub expected_method: reqwest::Method,
         pub expected_url: reqwest::Url,
         pub expected_headers: reqwest::header::HeaderMap,
         pub response: FakeResponse,
@@ -132,6 +551,7 @@ pub mod tests {
         ) -> FakeClient {
             let called = cell::Cell::new(false);
             FakeClient {
+                expected_method: reqwest::Method::GET,
                 expected_url,
                 expected_headers,
                 response,
@@ -148,13 +568,187 @@ pub mod tests {
         type Response = FakeResponse;
 
         fn execute(&self, request: Request) -> Result<Self::Response, Error> {
-            assert_eq!(request.method(), &reqwest::Method::GET);
+            assert_eq!(request.method(), &self.expected_method);
             assert_eq!(request.url(), &self.expected_url);
             assert_eq!(request.headers(), &self.expected_headers);
 
             self.called.set(true);
 
-            Ok(self.response.clone())
+            Ok(self.response.clone().responding_to(request.url()))
+        }
+    }
+
+    /// Answers asynchronous requests as though they were blocking requests.
+    #[cfg(feature = "async")]
+    impl super::AsyncClient for FakeClient {
+        type Response = FakeResponse;
+
+        fn execute(
+            &self,
+            request: reqwest::Request,
+        ) -> super::BoxFuture<'_, Result<Self::Response, Error>> {
+            let mut blocking =
+                Request::new(request.method().clone(), request.url().clone());
+            *blocking.headers_mut() = request.headers().clone();
+
+            Box::pin(std::future::ready(super::Client::execute(self, blocking)))
+        }
+    }
+
+    /// A client that serves canned responses for any number of URLs,
+    /// and can be shared between threads.
+    ///
+    /// URLs without a response get a 404.
+    #[derive(Debug, Default)]
+    pub struct FakeServer {
+        responses: HashMap<reqwest::Url, FakeResponse>,
+        delay: time::Duration,
+        calls: AtomicUsize,
+    }
+
+    impl FakeServer {
+        pub fn new() -> FakeServer {
+            Default::default()
+        }
+
+        pub fn add(&mut self, url: reqwest::Url, response: FakeResponse) {
+            self.responses.insert(url, response);
+        }
+
+        /// Take `delay` to respond to each request.
+        pub fn set_delay(&mut self, delay: time::Duration) {
+            self.delay = delay;
+        }
+
+        /// Return the number of requests received so far.
+        pub fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    impl super::Client for FakeServer {
+        type Response = FakeResponse;
+
+        fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+            assert!(
+                request.method() == reqwest::Method::GET
+                    || request.method() == reqwest::Method::HEAD
+            );
+
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(self.delay);
+
+            Ok(self
+                .responses
+                .get(request.url())
+                .cloned()
+                .unwrap_or_else(|| FakeResponse {
+                    status: StatusCode::NOT_FOUND,
+                    headers: reqwest::header::HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                    ..Default::default()
+                })
+                .responding_to(request.url()))
+        }
+    }
+
+    /// A client that serves canned responses like [`FakeServer`],
+    /// and remembers the URL and headers of every request.
+    #[derive(Debug, Default)]
+    pub struct RecordingServer {
+        inner: FakeServer,
+        requests: sync::Mutex<Vec<(reqwest::Url, reqwest::header::HeaderMap)>>,
+    }
+
+    impl RecordingServer {
+        pub fn new() -> RecordingServer {
+            Default::default()
+        }
+
+        pub fn add(&mut self, url: reqwest::Url, response: FakeResponse) {
+            self.inner.add(url, response);
+        }
+
+        /// Return the URL and headers of every request received so far.
+        pub fn requests(
+            &self,
+        ) -> Vec<(reqwest::Url, reqwest::header::HeaderMap)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl super::Client for RecordingServer {
+        type Response = FakeResponse;
+
+        fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((request.url().clone(), request.headers().clone()));
+            self.inner.execute(request)
+        }
+    }
+
+    /// A client that wraps another,
+    /// and says every response was received from a server
+    /// that presented `certificate`.
+    pub struct FakeTlsClient<C> {
+        pub inner: C,
+        pub certificate: Vec<u8>,
+    }
+
+    impl<C: super::Client> super::Client for FakeTlsClient<C> {
+        type Response = FakeTlsResponse<C::Response>;
+
+        fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+            Ok(FakeTlsResponse {
+                inner: self.inner.execute(request)?,
+                certificate: self.certificate.clone(),
+            })
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct FakeTlsResponse<R> {
+        inner: R,
+        certificate: Vec<u8>,
+    }
+
+    impl<R: super::HttpResponse> super::HttpResponse for FakeTlsResponse<R> {
+        fn headers(&self) -> &reqwest::header::HeaderMap {
+            self.inner.headers()
+        }
+        fn status(&self) -> StatusCode {
+            self.inner.status()
+        }
+        fn error_for_status(self) -> Result<Self, Error> {
+            Ok(FakeTlsResponse {
+                inner: self.inner.error_for_status()?,
+                certificate: self.certificate,
+            })
+        }
+        fn peer_certificate(&self) -> Option<&[u8]> {
+            Some(&self.certificate)
+        }
+        fn url(&self) -> &reqwest::Url {
+            self.inner.url()
+        }
+    }
+
+    impl<R: Read> Read for FakeTlsResponse<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    /// A client that fails the test if it is ever asked to send a request.
+    pub struct UnreachableClient;
+
+    impl super::Client for UnreachableClient {
+        type Response = FakeResponse;
+
+        fn execute(&self, request: Request) -> Result<Self::Response, Error> {
+            panic!("Unexpected request: {:?}", request);
         }
     }
 
@@ -207,4 +801,38 @@ pub mod tests {
             Err((self.make_error)())
         }
     }
+
+    #[test]
+    fn fake_response_reports_its_url() {
+        use super::{Client, HttpResponse};
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let response = FakeResponse {
+            status: StatusCode::OK,
+            headers: reqwest::header::HeaderMap::new(),
+            body: io::Cursor::new(vec![]),
+            ..Default::default()
+        };
+
+        let client = FakeClient::new(
+            url.clone(),
+            reqwest::header::HeaderMap::new(),
+            response.clone(),
+        );
+        let res =
+            Client::execute(&client, Request::new(Method::GET, url.clone()));
+        assert_eq!(HttpResponse::url(&res.unwrap()), &url);
+
+        let target: reqwest::Url = "http://example.com/final".parse().unwrap();
+        let client = FakeClient::new(
+            url.clone(),
+            reqwest::header::HeaderMap::new(),
+            FakeResponse {
+                url: Some(target.clone()),
+                ..response
+            },
+        );
+        let res = Client::execute(&client, Request::new(Method::GET, url));
+        assert_eq!(HttpResponse::url(&res.unwrap()), &target);
+    }
 }
@@ -0,0 +1,334 @@
+//! A cache that can be used from many threads at once.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path;
+use std::sync;
+
+use crate::error::Error;
+use crate::reqwest_mock;
+use crate::Cache;
+
+/// A local cache of HTTP resources that can be shared between threads.
+///
+/// This behaves like [`Cache`],
+/// except that its methods take `&self` instead of `&mut self`,
+/// so one `SharedCache` can be put in an `Arc`
+/// (or borrowed by scoped threads)
+/// and used from many threads at the same time.
+///
+/// Every call opens its own connection to the cache metadata,
+/// and all calls share the same `client`.
+/// Each call uses a [`Cache`] with the default settings,
+/// unless the `SharedCache` was created with
+/// [`with_settings`](Self::with_settings()).
+///
+/// If several threads ask for the same URL at the same time,
+/// only one of them contacts the server,
+/// and the others wait for it and use the copy it cached.
+pub struct SharedCache<C: reqwest_mock::Client> {
+    root: path::PathBuf,
+    client: C,
+    settings: Option<Settings<C>>,
+    in_flight: sync::Mutex<HashSet<reqwest::Url>>,
+    fetched: sync::Condvar,
+}
+
+/// A function that applies settings to a [`Cache`]
+/// opened by a [`SharedCache`].
+type Settings<C> =
+    Box<dyn Fn(&mut Cache<&C>) -> Result<(), Error> + Send + Sync>;
+
+/// Marks a URL as being fetched until it is dropped.
+struct InFlight<'a, C: reqwest_mock::Client> {
+    cache: &'a SharedCache<C>,
+    url: reqwest::Url,
+}
+
+impl<'a, C: reqwest_mock::Client> Drop for InFlight<'a, C> {
+    fn drop(&mut self) {
+        let mut in_flight = self.cache.in_flight.lock().unwrap();
+        in_flight.remove(&self.url);
+        self.cache.fetched.notify_all();
+    }
+}
+
+impl<C> fmt::Debug for SharedCache<C>
+where
+    C: reqwest_mock::Client + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedCache")
+            .field("root", &self.root)
+            .field("client", &self.client)
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+impl<C: reqwest_mock::Client> SharedCache<C> {
+    /// Returns a SharedCache that wraps `client` and caches data in `root`.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::new`] might.
+    pub fn new(
+        root: path::PathBuf,
+        client: C,
+    ) -> Result<SharedCache<C>, Error> {
+        // Make sure the directory and the metadata schema exist
+        // before any threads start racing to create them.
+        Cache::new(root.clone(), &client)?;
+
+        Ok(SharedCache {
+            root,
+            client,
+            settings: None,
+            in_flight: sync::Mutex::new(HashSet::new()),
+            fetched: sync::Condvar::new(),
+        })
+    }
+
+    /// Returns a SharedCache like [`new`](Self::new()),
+    /// whose [`Cache`]s are set up by `settings`.
+    ///
+    /// Each call opens its own [`Cache`],
+    /// so there's no one [`Cache`] to change the settings of.
+    /// Instead, `settings` is called on every [`Cache`] as it's opened,
+    /// so that settings like [`Cache::set_key_policy`]
+    /// or [`Cache::set_self_heal`] apply to every call.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::new`] might,
+    /// or any error `settings` returns.
+    /// Later calls return any error `settings` returns then, too.
+    pub fn with_settings(
+        root: path::PathBuf,
+        client: C,
+        settings: Settings<C>,
+    ) -> Result<SharedCache<C>, Error> {
+        let mut res = SharedCache::new(root, client)?;
+        res.settings = Some(settings);
+
+        // Report bad settings now, rather than on first use.
+        res.cache()?;
+
+        Ok(res)
+    }
+
+    /// Retrieve the content of the given URL.
+    ///
+    /// See [`Cache::get`] for details.
+    ///
+    /// Errors
+    /// ======
+    ///
+    /// This method may return an error for any of the reasons
+    /// [`Cache::get`] might.
+    pub fn get(&self, mut url: reqwest::Url) -> Result<fs::File, Error> {
+        url.set_fragment(None);
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains(&url) {
+            // Another thread is already fetching this URL,
+            // so wait for it to finish and use what it cached.
+            while in_flight.contains(&url) {
+                in_flight = self.fetched.wait(in_flight).unwrap();
+            }
+            drop(in_flight);
+
+            if let Some(file) = self.cache()?.open_cached(&url)? {
+                return Ok(file);
+            }
+
+            // The other thread didn't manage it, so let's try ourselves.
+            return self.cache()?.get(url);
+        }
+
+        in_flight.insert(url.clone());
+        drop(in_flight);
+
+        let _in_flight = InFlight {
+            cache: self,
+            url: url.clone(),
+        };
+
+        self.cache()?.get(url)
+    }
+
+    /// Open a [`Cache`] on our root directory
+    /// that borrows our client,
+    /// with our settings applied.
+    fn cache(&self) -> Result<Cache<&C>, Error> {
+        let mut res = Cache::new(self.root.clone(), &self.client)?;
+        if let Some(settings) = &self.settings {
+            settings(&mut res)?;
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+    use std::sync;
+    use std::thread;
+    use std::time;
+
+    use reqwest::header::HeaderMap;
+
+    use super::SharedCache;
+    use crate::reqwest_mock::tests as rmt;
+
+    #[test]
+    fn concurrent_requests_for_one_url_are_coalesced() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            reqwest::header::CACHE_CONTROL,
+            reqwest::header::HeaderValue::from_static("max-age=3600"),
+        );
+
+        let mut server = rmt::FakeServer::new();
+        server.add(
+            url.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+                ..Default::default()
+            },
+        );
+        server.set_delay(time::Duration::from_millis(50));
+
+        let c = SharedCache::new(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            server,
+        )
+        .unwrap();
+
+        let start = sync::Barrier::new(8);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                let (c, url, start) = (&c, &url, &start);
+                scope.spawn(move || {
+                    start.wait();
+                    let mut res = c.get(url.clone()).unwrap();
+                    let mut buf = vec![];
+                    res.read_to_end(&mut buf).unwrap();
+                    assert_eq!(buf, b"hello");
+                });
+            }
+        });
+
+        assert_eq!(c.client.calls(), 1);
+    }
+
+    #[test]
+    fn share_cache_between_threads() {
+        let _ = env_logger::try_init();
+
+        let urls: Vec<reqwest::Url> = (0..4)
+            .map(|i| format!("http://example.com/{}", i).parse().unwrap())
+            .collect();
+
+        let mut server = rmt::FakeServer::new();
+        for url in &urls {
+            server.add(
+                url.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(url.path().as_bytes().into()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let c = SharedCache::new(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            server,
+        )
+        .unwrap();
+
+        thread::scope(|scope| {
+            for url in &urls {
+                let c = &c;
+                scope.spawn(move || {
+                    let mut res = c.get(url.clone()).unwrap();
+                    let mut buf = vec![];
+                    res.read_to_end(&mut buf).unwrap();
+                    assert_eq!(buf, url.path().as_bytes());
+                });
+            }
+        });
+
+        // Everything should have been cached.
+        let cache = c.cache().unwrap();
+        for url in urls {
+            assert_eq!(cache.body_len(&url).unwrap(), Some(2));
+        }
+    }
+
+    #[test]
+    fn settings_apply_to_every_call() {
+        let _ = env_logger::try_init();
+
+        let urls: Vec<reqwest::Url> = (0..2)
+            .map(|i| format!("http://example.com/{}", i).parse().unwrap())
+            .collect();
+
+        let mut server = rmt::RecordingServer::new();
+        for url in &urls {
+            server.add(url.clone(), rmt::FakeResponse::default());
+        }
+
+        let c = SharedCache::with_settings(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            server,
+            Box::new(|cache| cache.set_user_agent("shared-test".into())),
+        )
+        .unwrap();
+
+        for url in &urls {
+            c.get(url.clone()).unwrap();
+        }
+
+        let requests = c.client.requests();
+        assert_eq!(requests.len(), 2);
+        for (_, headers) in requests {
+            assert_eq!(headers[reqwest::header::USER_AGENT], "shared-test");
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_reported_when_created() {
+        let _ = env_logger::try_init();
+
+        let res = SharedCache::with_settings(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeServer::new(),
+            Box::new(|cache| cache.set_user_agent("bad\nagent".into())),
+        );
+
+        assert!(res.is_err());
+    }
+}